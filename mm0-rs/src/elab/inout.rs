@@ -1,18 +1,96 @@
 //! Support for the `input` and `output` commands.
 
 use std::io;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
 use super::proof::{Dedup, NodeHasher, build};
 use super::environment::{DeclKey, SortID, TermID, Type, Expr, ExprNode,
-  OutputString, StmtTrace, Environment};
+  OutputString, InputString, StmtTrace, Environment};
 use super::{ElabError, Elaborator, Span, HashMap, Result as EResult, SExpr,
   lisp::{InferTarget, LispVal}, local_context::try_get_span, FrozenEnv};
 use crate::util::{FileSpan, BoxError};
 
-/// The elaboration data used by input/output commands. This caches precomputed
-/// evaluations of `output string` commands.
-#[derive(Default, Debug)]
+/// A backend for the `output` command, dispatched on the kind name written after
+/// `output` (e.g. the `string` in `output string $e$`). Implementing this and
+/// registering it (via [`InoutHandlers::register_output`] at elaboration time,
+/// or by inserting into the [`OutputHandlers`] map passed to
+/// [`FrozenEnv::run_output_with`]) lets new output kinds (a hex-dump form, an
+/// array-of-bytes form, ...) be added without touching the elaborator core.
+pub trait OutputHandler {
+  /// Elaborate an `output <kind> $e1$ ... $en$` command at `sp`, pushing the
+  /// resulting statement onto `elab.stmts`.
+  fn elaborate(&self, elab: &mut Elaborator, sp: Span, hs: &[SExpr]) -> EResult<()>;
+  /// Render a `StmtTrace::OutputString` previously produced by `elaborate` for
+  /// this kind, writing its bytes to `w`. `w` is threaded through the whole run
+  /// so a dangling hex nibble at a statement boundary still carries over into
+  /// the next one, just as it does within a single statement.
+  fn render(&self, env: &Environment, os: &OutputString,
+    w: &mut StringWriter<&mut dyn io::Write>, bs: &BudgetState) -> Result<(), OutputError>;
+}
+
+#[derive(Debug, Default)]
+struct StringOutputHandler;
+
+impl OutputHandler for StringOutputHandler {
+  fn elaborate(&self, elab: &mut Elaborator, sp: Span, hs: &[SExpr]) -> EResult<()> {
+    elab.elab_output_string(sp, hs)
+  }
+
+  fn render(&self, env: &Environment, os: &OutputString,
+      w: &mut StringWriter<&mut dyn io::Write>, bs: &BudgetState) -> Result<(), OutputError> {
+    let (_, _, terms) = env.new_string_handler()?;
+    env.write_output_string(&terms, w, &os.heap, &os.exprs, bs)
+  }
+}
+
+/// A registry of output kind handlers, keyed by the kind name written after
+/// `output` (e.g. `"string"`). Shared between [`InoutHandlers`] (the
+/// elaboration-time registry) and [`FrozenEnv::run_output_with`] (which
+/// renders against whichever registry the caller built at elaboration time,
+/// rather than silently falling back to the builtins).
+pub type OutputHandlers = HashMap<&'static str, Rc<dyn OutputHandler>>;
+
+/// The registry of builtin output kinds.
+pub fn builtin_output_handlers() -> OutputHandlers {
+  let mut m: OutputHandlers = HashMap::new();
+  m.insert("string", Rc::new(StringOutputHandler));
+  m
+}
+
+/// The elaboration data used by input/output commands. This caches the `string`
+/// sort/term IDs together with the compiled [`StringSeg`] bodies of the
+/// `string`-valued `def`s that flatten, so that `output string` evaluation
+/// doesn't have to rediscover them on every call, plus the registry of output
+/// kind handlers. The cache is built once, on first use; defs declared after
+/// that point are not in it and are expanded from their `Expr` instead.
 pub struct InoutHandlers {
-  string: Option<(Sorts, HashMap<TermID, InoutStringType>)>
+  string: Option<(Sorts, StringTerms, HashMap<TermID, InoutStringType>)>,
+  output: OutputHandlers,
+}
+
+impl InoutHandlers {
+  /// Register a handler for `kind` (e.g. `"hex"`), overriding any existing
+  /// handler (builtin or otherwise) for that name. Call this while
+  /// elaborating, then pass [`Elaborator::output_handlers`] through to
+  /// [`FrozenEnv::run_output_with`] so the same registry is used to render.
+  pub fn register_output(&mut self, kind: &'static str, handler: Rc<dyn OutputHandler>) {
+    self.output.insert(kind, handler);
+  }
+}
+
+impl Default for InoutHandlers {
+  fn default() -> Self {
+    InoutHandlers {string: None, output: builtin_output_handlers()}
+  }
+}
+
+impl std::fmt::Debug for InoutHandlers {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("InoutHandlers")
+      .field("string", &self.string)
+      .field("output", &self.output.keys().collect::<Vec<_>>())
+      .finish()
+  }
 }
 
 #[derive(Debug)]
@@ -23,8 +101,13 @@ enum InoutStringType {
   SCons,
   Ch,
   Hex(u8),
-  // Str(Box<[u8]>),
-  // Gen(usize, Box<[StringSeg]>),
+  /// A def that flattens to a constant byte string, independent of its arguments
+  /// (e.g. it has none, or ignores the ones it has).
+  Str(Box<[u8]>),
+  /// A def that flattens to a fixed `StringSeg` program over `usize` arguments.
+  /// `write_node` can replay this program directly instead of re-walking
+  /// the def's `Expr` on every call site.
+  Gen(usize, Box<[StringSeg]>),
 }
 
 #[derive(Clone, Debug, EnvDebug, PartialEq, Eq)]
@@ -35,6 +118,43 @@ enum StringSeg {
   Hex(u8),
 }
 
+/// A not-yet-evaluated argument to a def call, evaluated left-to-right at its
+/// first point of use rather than ahead of time. Its bytes are built in a
+/// separate buffer, cached in the enclosing [`ArgSlot`] so that an argument
+/// referenced more than once is derived only once, and then copied to the
+/// final `w`; they are not streamed into `w` directly.
+#[derive(Clone, Copy, Debug)]
+enum Arg<'a> {
+  /// Evaluate `ExprNode` `.0` against the heap `.1` of the call site it came from.
+  Node(&'a ExprNode, &'a [ArgSlot<'a>]),
+  /// Evaluate `ExprNode` `.0` against the first `.1` entries of *this same*
+  /// heap vector (a def's own "let"-like entries can only refer to earlier
+  /// ones, so this avoids borrowing the vector while it is still being built).
+  Local(&'a ExprNode, usize),
+  /// Replay `StringSeg` program `.0` (the flattened body of a `Gen` def)
+  /// against the arg list `.1` it was called with.
+  Seg(&'a [StringSeg], &'a [ArgSlot<'a>]),
+}
+
+/// An [`Arg`] with a cache of its result, populated on first evaluation and
+/// replayed on every later reference to it.
+#[derive(Debug)]
+struct ArgSlot<'a> {
+  arg: Arg<'a>,
+  cache: RefCell<Option<Rc<CachedArg>>>,
+}
+
+impl<'a> ArgSlot<'a> {
+  fn new(arg: Arg<'a>) -> Self { ArgSlot {arg, cache: RefCell::new(None)} }
+}
+
+/// The bytes (and dangling hex nibble, if any) produced by evaluating an `ArgSlot`.
+#[derive(Debug)]
+struct CachedArg {
+  bytes: Box<[u8]>,
+  hex: Option<u8>,
+}
+
 #[derive(Default, Debug)]
 struct StringSegBuilder {
   built: Vec<StringSeg>,
@@ -85,8 +205,15 @@ impl StringSegBuilder {
 pub enum OutputError {
   /// The underlying writer throwed an IO error
   IOError(io::Error),
-  /// There was a logical error preventing the output to be written
+  /// There was a logical error preventing the output to be written, not (yet)
+  /// attributable to anything more specific than the enclosing statement.
   String(String),
+  /// Like `String`, but attributed to the span of the definition whose
+  /// expansion raised it, rather than the enclosing `output`/`input` statement.
+  /// `write_node`/`write_segs` attach this as soon as they know which def is to
+  /// blame; an error that already carries a span is left alone as it propagates
+  /// further up, so the innermost (most specific) span wins.
+  Spanned(FileSpan, String),
 }
 
 impl From<io::Error> for OutputError {
@@ -101,47 +228,95 @@ impl Into<BoxError> for OutputError {
     match self {
       OutputError::IOError(e) => e.into(),
       OutputError::String(s) => s.into(),
+      OutputError::Spanned(_, s) => s.into(),
     }
   }
 }
 
-#[derive(Default)]
-struct StringWriter<W> {
-  w: W,
-  hex: Option<u8>,
+/// Limits on the work done expanding a `string` def during `output`/`input`
+/// evaluation, so a pathological or huge def aborts cleanly instead of
+/// exhausting memory or the call stack.
+#[derive(Copy, Clone, Debug)]
+pub struct Budget {
+  /// The maximum number of bytes that may be written to the final output.
+  pub max_bytes: usize,
+  /// The maximum depth of nested `write_node`/`write_segs` calls.
+  pub max_depth: usize,
 }
 
-#[allow(variant_size_differences)]
-enum StringPart {
-  Hex(u8),
-  Str(Vec<u8>)
+impl Default for Budget {
+  fn default() -> Self { Budget {max_bytes: 1 << 28, max_depth: 1024} }
 }
 
-impl<W: io::Write> StringWriter<W> {
-  fn write_hex(&mut self, h: u8) -> Result<(), OutputError> {
-    match self.hex.take() {
-      None => self.hex = Some(h),
-      Some(hi) => self.w.write_all(&[hi << 4 | h])?
+/// The recursion depth limit for `process_node`/`process_def`, which flatten
+/// `string`-valued `def`s by plain Rust recursion at elaboration time, before
+/// any [`Budget`] exists to guard against it.
+const MAX_DEF_DEPTH: usize = 1024;
+
+/// Tracks consumption of a [`Budget`] across every `write_node`/`write_segs`
+/// call sharing this state. `run_output` shares one across all of a file's
+/// `output` statements; `eval_string` creates a fresh one per call.
+#[derive(Debug)]
+pub(crate) struct BudgetState {
+  budget: Budget,
+  bytes: Cell<usize>,
+  depth: Cell<usize>,
+}
+
+/// Decrements the depth counter on drop, so an early return still leaves it correct.
+struct DepthGuard<'a>(&'a BudgetState);
+impl Drop for DepthGuard<'_> {
+  fn drop(&mut self) { self.0.depth.set(self.0.depth.get() - 1); }
+}
+
+impl BudgetState {
+  fn new(budget: Budget) -> Self {
+    BudgetState {budget, bytes: Cell::new(0), depth: Cell::new(0)}
+  }
+
+  fn add_bytes(&self, n: usize) -> Result<(), OutputError> {
+    let bytes = self.bytes.get() + n;
+    self.bytes.set(bytes);
+    if bytes > self.budget.max_bytes {
+      return Err(format!(
+        "output expansion exceeded the {} byte budget", self.budget.max_bytes).as_str().into())
     }
     Ok(())
   }
-  fn write_str(&mut self, buf: &[u8]) -> Result<(), OutputError> {
-    Ok(self.w.write_all(buf)?)
-  }
-  fn write_part(&mut self, s: &StringPart) -> Result<(), OutputError> {
-    match s {
-      &StringPart::Hex(h) => self.write_hex(h),
-      StringPart::Str(s) => self.write_str(s),
+
+  fn enter(&self) -> Result<DepthGuard<'_>, OutputError> {
+    let depth = self.depth.get() + 1;
+    if depth > self.budget.max_depth {
+      return Err(format!(
+        "output expansion exceeded the recursion depth budget of {}", self.budget.max_depth).as_str().into())
     }
+    self.depth.set(depth);
+    Ok(DepthGuard(self))
   }
 }
 
-impl From<StringWriter<Vec<u8>>> for StringPart {
-  fn from(s: StringWriter<Vec<u8>>) -> Self {
-    match s.hex {
-      None => StringPart::Str(s.w),
-      Some(h) => StringPart::Hex(h),
+#[derive(Default)]
+pub(crate) struct StringWriter<W> {
+  w: W,
+  hex: Option<u8>,
+}
+
+impl<W: io::Write> StringWriter<W> {
+  /// Writes a hex nibble, carrying it across calls (and across statement
+  /// boundaries, since `w` is threaded through the whole `run_output` loop)
+  /// until a second nibble arrives to complete a byte. Only a completed byte
+  /// is charged against `bs`, but since a lone trailing nibble can only ever
+  /// contribute to one more byte this undercounts by at most 1.
+  fn write_hex(&mut self, h: u8, bs: &BudgetState) -> Result<(), OutputError> {
+    match self.hex.take() {
+      None => self.hex = Some(h),
+      Some(hi) => { self.w.write_all(&[hi << 4 | h])?; bs.add_bytes(1)?; }
     }
+    Ok(())
+  }
+  fn write_str(&mut self, buf: &[u8], bs: &BudgetState) -> Result<(), OutputError> {
+    self.w.write_all(buf)?;
+    bs.add_bytes(buf.len())
   }
 }
 
@@ -152,12 +327,39 @@ struct Sorts {
   chr: SortID,
 }
 
+/// The concrete `TermID`s backing the `string` handler's builtin terms, needed
+/// (in addition to the classification map) to build fresh `string` terms
+/// rather than just read existing ones, as `decode_string` does.
+#[derive(Copy, Clone, Debug, EnvDebug)]
+struct StringTerms {
+  s0: TermID,
+  s1: TermID,
+  sadd: TermID,
+  ch: TermID,
+  hex: [TermID; 16],
+}
+
+/// A compile-time check error, optionally carrying the span of the specific
+/// declaration at fault so the caller can report against that instead of the
+/// whole enclosing `output`/`input` command (there is no such declaration to
+/// point at for e.g. a sort/term that is missing entirely).
+type CheckError = (String, Option<FileSpan>);
+
+impl From<CheckError> for OutputError {
+  fn from((e, span): CheckError) -> Self {
+    match span {
+      Some(fsp) => OutputError::Spanned(fsp, e),
+      None => OutputError::String(e),
+    }
+  }
+}
+
 impl Environment {
-  fn check_sort(&self, s: &str) -> Result<SortID, String> {
+  fn check_sort(&self, s: &str) -> Result<SortID, CheckError> {
     self.atoms.get(s).and_then(|&a| self.data[a].sort)
-      .ok_or_else(|| format!("sort '{}' not found", s))
+      .ok_or_else(|| (format!("sort '{}' not found", s), None))
   }
-  fn new_sorts(&self) -> Result<Sorts, String> {
+  fn new_sorts(&self) -> Result<Sorts, CheckError> {
     Ok(Sorts {
       str: self.check_sort("string")?,
       hex: self.check_sort("hex")?,
@@ -166,14 +368,14 @@ impl Environment {
   }
 
   fn check_term<'a>(&'a self, s: &str,
-      args: &[SortID], ret: SortID, def: bool) -> Result<TermID, String> {
+      args: &[SortID], ret: SortID, def: bool) -> Result<TermID, CheckError> {
     let t = self.atoms.get(s)
       .and_then(|&a| if let Some(DeclKey::Term(t)) = self.data[a].decl {Some(t)} else {None})
-      .ok_or_else(|| format!("term '{}' not found", s))?;
+      .ok_or_else(|| (format!("term '{}' not found", s), None))?;
     let td = &self.terms[t];
     match (def, &td.val) {
-      (false, Some(_)) => return Err(format!("def '{}' should be a term", s)),
-      (true, None) => return Err(format!("term '{}' should be a def", s)),
+      (false, Some(_)) => return Err((format!("def '{}' should be a term", s), Some(td.span.clone()))),
+      (true, None) => return Err((format!("term '{}' should be a def", s), Some(td.span.clone()))),
       _ => {}
     }
     let ok = td.ret == (ret, 0) &&
@@ -186,7 +388,7 @@ impl Environment {
         write!(s, "{} > ", self.data[self.sorts[i].atom].name).unwrap();
       }
       write!(s, "{}", self.data[self.sorts[ret].atom].name).unwrap();
-      return Err(s)
+      return Err((s, Some(td.span.clone())))
     }
     Ok(t)
   }
@@ -196,7 +398,12 @@ impl Environment {
     args: &[(T, Type)], e: &ExprNode,
     heap: &[Box<[StringSeg]>],
     out: &mut StringSegBuilder,
+    depth: usize,
   ) -> Result<(), String> {
+    if depth > MAX_DEF_DEPTH {
+      return Err(format!(
+        "def nesting exceeded the depth limit of {} while flattening", MAX_DEF_DEPTH))
+    }
     match e {
       ExprNode::Dummy(_, _) => return Err("dummy not permitted".into()),
       &ExprNode::Ref(i) => match i.checked_sub(args.len()) {
@@ -210,18 +417,18 @@ impl Environment {
       &ExprNode::App(t, ref ns) => match terms.get(&t) {
         Some(InoutStringType::S0) => {}
         Some(InoutStringType::S1) =>
-          self.process_node(terms, args, &ns[0], heap, out)?,
+          self.process_node(terms, args, &ns[0], heap, out, depth + 1)?,
         Some(InoutStringType::SAdd) |
         Some(InoutStringType::SCons) |
         Some(InoutStringType::Ch) => {
-          self.process_node(terms, args, &ns[0], heap, out)?;
-          self.process_node(terms, args, &ns[1], heap, out)?;
+          self.process_node(terms, args, &ns[0], heap, out, depth + 1)?;
+          self.process_node(terms, args, &ns[1], heap, out, depth + 1)?;
         }
         Some(&InoutStringType::Hex(h)) => {out.push_hex(h);}
-        // Some(InoutStringType::Str(s)) => {out.push_str(s);}
+        Some(InoutStringType::Str(s)) => {out.push_str(s);}
         _ => {
           let args = ns.iter().map(|n| StringSegBuilder::make(|arg|
-              self.process_node(terms, args, n, heap, arg)))
+              self.process_node(terms, args, n, heap, arg, depth + 1)))
             .collect::<Result<Vec<_>, _>>()?.into_boxed_slice();
           out.push_seg(StringSeg::Term(t, args));
         }
@@ -230,59 +437,154 @@ impl Environment {
     Ok(())
   }
 
-  fn write_node<W: io::Write>(&self,
+  /// Evaluate `slot` (resolving a `Local` against `ctx`) and write the result
+  /// to `w`, caching it in `slot` on first evaluation so a repeated reference
+  /// replays the cached bytes instead of re-deriving them.
+  fn write_arg<'a, W: io::Write>(&self,
+    terms: &HashMap<TermID, InoutStringType>,
+    ctx: &[ArgSlot<'a>],
+    slot: &ArgSlot<'a>,
+    w: &mut StringWriter<W>,
+    bs: &BudgetState,
+  ) -> Result<(), OutputError> {
+    if slot.cache.borrow().is_none() {
+      // Bytes are charged below, once per actual use, not here, so a value
+      // referenced many times still costs budget proportional to its uses.
+      // The buffer itself is still capped at `max_bytes`: a value larger
+      // than that would exceed the budget once written anyway.
+      let free = BudgetState {
+        budget: Budget {max_bytes: bs.budget.max_bytes, max_depth: bs.budget.max_depth},
+        bytes: Cell::new(0),
+        depth: Cell::new(bs.depth.get()),
+      };
+      let mut buf = StringWriter::<Vec<u8>>::default();
+      match slot.arg {
+        Arg::Node(e, heap) => self.write_node(terms, heap, e, &mut buf, &free)?,
+        Arg::Local(e, n) => self.write_node(terms, &ctx[..n], e, &mut buf, &free)?,
+        Arg::Seg(segs, args) => self.write_segs(terms, args, segs, &mut buf, &free)?,
+      }
+      *slot.cache.borrow_mut() = Some(Rc::new(CachedArg {bytes: buf.w.into_boxed_slice(), hex: buf.hex}));
+    }
+    let cached = slot.cache.borrow().clone().expect("just populated above");
+    w.write_str(&cached.bytes, bs)?;
+    if let Some(h) = cached.hex { w.write_hex(h, bs)?; }
+    Ok(())
+  }
+
+  fn write_node<'a, W: io::Write>(&self,
     terms: &HashMap<TermID, InoutStringType>,
-    heap: &[StringPart],
-    e: &ExprNode,
+    heap: &[ArgSlot<'a>],
+    e: &'a ExprNode,
     w: &mut StringWriter<W>,
+    bs: &BudgetState,
   ) -> Result<(), OutputError> {
+    let _guard = bs.enter()?;
     match e {
       ExprNode::Dummy(_, _) => Err("Found dummy variable in string definition".into()),
-      &ExprNode::Ref(i) => w.write_part(&heap[i]),
+      &ExprNode::Ref(i) => self.write_arg(terms, heap, &heap[i], w, bs),
       &ExprNode::App(t, ref ns) => match terms.get(&t) {
         Some(InoutStringType::S0) => Ok(()),
         Some(InoutStringType::S1) =>
-          self.write_node(terms, heap, &ns[0], w),
+          self.write_node(terms, heap, &ns[0], w, bs),
         Some(InoutStringType::SAdd) |
         Some(InoutStringType::SCons) |
         Some(InoutStringType::Ch) => {
-          self.write_node(terms, heap, &ns[0], w)?;
-          self.write_node(terms, heap, &ns[1], w)
+          self.write_node(terms, heap, &ns[0], w, bs)?;
+          self.write_node(terms, heap, &ns[1], w, bs)
         }
-        Some(&InoutStringType::Hex(h)) => w.write_hex(h),
-        _ => if let Some(Some(expr)) = &self.terms[t].val {
-          let mut args: Vec<StringPart> = Vec::with_capacity(heap.len());
-          for e in &**ns {
-            let mut w = StringWriter::default();
-            self.write_node(terms, heap, e, &mut w)?;
-            args.push(w.into());
-          }
+        Some(&InoutStringType::Hex(h)) => w.write_hex(h, bs),
+        Some(InoutStringType::Str(s)) => w.write_str(s, bs),
+        Some(InoutStringType::Gen(arity, segs)) => {
+          debug_assert_eq!(ns.len(), *arity);
+          let args: Vec<ArgSlot<'a>> = ns.iter().map(|e| ArgSlot::new(Arg::Node(e, heap))).collect();
+          self.write_segs(terms, &args, segs, w, bs).map_err(|e| self.blame(t, e))
+        }
+        None => if let Some(Some(expr)) = &self.terms[t].val {
+          let mut args: Vec<ArgSlot<'a>> = ns.iter().map(|e| ArgSlot::new(Arg::Node(e, heap))).collect();
           for e in &expr.heap[ns.len()..] {
-            let mut w = StringWriter::default();
-            self.write_node(terms, &args, e, &mut w)?;
-            args.push(w.into());
+            let n = args.len();
+            args.push(ArgSlot::new(Arg::Local(e, n)));
           }
-          self.write_node(terms, &args, &expr.head, w)
+          self.write_node(terms, &args, &expr.head, w, bs).map_err(|e| self.blame(t, e))
         } else {
-          Err("Unknown definition".into())
+          Err(self.blame(t, "Unknown definition".into()))
         }
       }
     }
   }
 
+  /// Attribute `e` to the span of the definition `t`, if it isn't already
+  /// attributed to something more specific deeper in the call stack.
+  fn blame(&self, t: TermID, e: OutputError) -> OutputError {
+    match e {
+      OutputError::String(s) => OutputError::Spanned(self.terms[t].span.clone(), s),
+      e => e,
+    }
+  }
+
+  /// Replay a cached `StringSeg` program (the flattened body of a `Gen` def)
+  /// against the arg list `args` it was called with, writing the result to
+  /// `w` without re-walking the originating `Expr`.
+  fn write_segs<'a, W: io::Write>(&self,
+    terms: &HashMap<TermID, InoutStringType>,
+    args: &[ArgSlot<'a>],
+    segs: &'a [StringSeg],
+    w: &mut StringWriter<W>,
+    bs: &BudgetState,
+  ) -> Result<(), OutputError> {
+    let _guard = bs.enter()?;
+    for seg in segs {
+      match seg {
+        StringSeg::Str(s) => w.write_str(s, bs)?,
+        &StringSeg::Hex(h) => w.write_hex(h, bs)?,
+        &StringSeg::Var(_, i) => self.write_arg(terms, args, &args[i as usize], w, bs)?,
+        StringSeg::Term(t, arg_segs) => {
+          let sub_args: Vec<ArgSlot<'a>> = arg_segs.iter().map(|a| ArgSlot::new(Arg::Seg(a, args))).collect();
+          match terms.get(t) {
+            Some(InoutStringType::S0) => {}
+            Some(InoutStringType::S1) => self.write_arg(terms, &sub_args, &sub_args[0], w, bs)?,
+            Some(InoutStringType::SAdd) |
+            Some(InoutStringType::SCons) |
+            Some(InoutStringType::Ch) => {
+              self.write_arg(terms, &sub_args, &sub_args[0], w, bs)?;
+              self.write_arg(terms, &sub_args, &sub_args[1], w, bs)?;
+            }
+            Some(&InoutStringType::Hex(h)) => w.write_hex(h, bs)?,
+            Some(InoutStringType::Str(s)) => w.write_str(s, bs)?,
+            Some(InoutStringType::Gen(arity, segs)) => {
+              debug_assert_eq!(arg_segs.len(), *arity);
+              self.write_segs(terms, &sub_args, segs, w, bs).map_err(|e| self.blame(*t, e))?
+            }
+            None => if let Some(Some(expr)) = &self.terms[*t].val {
+              let mut heap = sub_args;
+              for e in &expr.heap[heap.len()..] {
+                let n = heap.len();
+                heap.push(ArgSlot::new(Arg::Local(e, n)));
+              }
+              self.write_node(terms, &heap, &expr.head, w, bs).map_err(|e| self.blame(*t, e))?;
+            } else {
+              return Err(self.blame(*t, "Unknown definition".into()))
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
   fn write_output_string<W: io::Write>(&self,
     terms: &HashMap<TermID, InoutStringType>,
     w: &mut StringWriter<W>,
-    heap: &[ExprNode], exprs: &[ExprNode]
+    heap: &[ExprNode], exprs: &[ExprNode],
+    bs: &BudgetState,
   ) -> Result<(), OutputError> {
     let mut args = Vec::with_capacity(heap.len());
     for e in heap {
-      let mut w = StringWriter::default();
-      self.write_node(terms, &args, e, &mut w)?;
-      args.push(w.into());
+      let n = args.len();
+      args.push(ArgSlot::new(Arg::Local(e, n)));
     }
     for e in exprs {
-      self.write_node(terms, &args, e, w)?;
+      self.write_node(terms, &args, e, w, bs)?;
     }
     Ok(())
   }
@@ -295,26 +597,33 @@ impl Environment {
       let mut refs = Vec::with_capacity(heap.len() - td.args.len());
       for e in &heap[td.args.len()..] {
         let res = StringSegBuilder::make(|out|
-          self.process_node(terms, &td.args, e, &refs, out))?;
+          self.process_node(terms, &td.args, e, &refs, out, 0))?;
         refs.push(res);
       }
       StringSegBuilder::make(|out|
-        self.process_node(terms, &td.args, head, &refs, out))
+        self.process_node(terms, &td.args, head, &refs, out, 0))
     } else {
       Err(format!("term '{}' should be a def", name))
     }
   }
 
-  fn new_string_handler(&self) -> Result<(Sorts, HashMap<TermID, InoutStringType>), String> {
+  fn new_string_handler(&self) -> Result<(Sorts, StringTerms, HashMap<TermID, InoutStringType>), CheckError> {
     let s = self.new_sorts()?;
     let mut map = HashMap::new();
     use InoutStringType::*;
-    map.insert(self.check_term("s0", &[], s.str, false)?, S0);
-    map.insert(self.check_term("s1", &[s.chr], s.str, false)?, S1);
-    map.insert(self.check_term("sadd", &[s.str, s.str], s.str, false)?, SAdd);
-    map.insert(self.check_term("ch", &[s.hex, s.hex], s.chr, false)?, Ch);
+    let s0 = self.check_term("s0", &[], s.str, false)?;
+    let s1 = self.check_term("s1", &[s.chr], s.str, false)?;
+    let sadd = self.check_term("sadd", &[s.str, s.str], s.str, false)?;
+    let ch = self.check_term("ch", &[s.hex, s.hex], s.chr, false)?;
+    map.insert(s0, S0);
+    map.insert(s1, S1);
+    map.insert(sadd, SAdd);
+    map.insert(ch, Ch);
+    let mut hex = [s0; 16];
     for i in 0..16 {
-      map.insert(self.check_term(&format!("x{:x}", i), &[], s.hex, false)?, Hex(i));
+      let t = self.check_term(&format!("x{:x}", i), &[], s.hex, false)?;
+      map.insert(t, Hex(i));
+      hex[i as usize] = t;
     }
     if let Ok(t) = self.check_term("scons", &[s.chr, s.str], s.str, true) {
       if let Ok(ss) = self.process_def(&map, t, "scons") {
@@ -323,22 +632,72 @@ impl Environment {
         }
       }
     }
-    Ok((s, map))
+    self.compile_string_defs(s, &mut map);
+    Ok((s, StringTerms {s0, s1, sadd, ch, hex}, map))
+  }
+
+  /// Decode `bytes` into the canonical `string` term, as the dual of
+  /// `write_node`: each byte splits into two hex nibbles wrapped with
+  /// `ch(x_hi, x_lo)`, the resulting chars are consed together with
+  /// `sadd(s1(_), _)` from the back, and the empty string is `s0`. Each
+  /// partial result is pushed onto the heap and referenced by `Ref`, like
+  /// `elab_output_string`'s `Dedup`-built `Expr`s, so the result is flat
+  /// rather than a tree as deep as `bytes` is long.
+  fn decode_string(&self, terms: &StringTerms, bytes: &[u8]) -> Expr {
+    let mut heap = vec![ExprNode::App(terms.s0, Box::new([]))];
+    let mut cur = 0;
+    for &b in bytes.iter().rev() {
+      let hi = ExprNode::App(terms.hex[(b >> 4) as usize], Box::new([]));
+      let lo = ExprNode::App(terms.hex[(b & 0xf) as usize], Box::new([]));
+      let c = ExprNode::App(terms.ch, Box::new([hi, lo]));
+      let c = ExprNode::App(terms.s1, Box::new([c]));
+      heap.push(ExprNode::App(terms.sadd, Box::new([c, ExprNode::Ref(cur)])));
+      cur = heap.len() - 1;
+    }
+    Expr {heap: heap.into_boxed_slice(), head: ExprNode::Ref(cur)}
+  }
+
+  /// Flatten every `string`-valued `def` into a cached [`StringSeg`] program,
+  /// so that `write_node` can replay the template directly on every call site
+  /// instead of re-walking the def's `Expr` heap each time. Defs are visited in
+  /// increasing `TermID` order, which coincides with dependency order here
+  /// because MM0 defs can only refer to previously declared terms (the kernel
+  /// forbids recursive defs), so by the time we reach a def its dependencies
+  /// are already classified and `process_def` can inline any that turned out
+  /// to be constant (`Str`). A def that fails to flatten (e.g. it involves a
+  /// `Dummy`, or an argument/sort shape `process_node` doesn't understand) is
+  /// simply left unclassified; `write_node` falls back to expanding its `Expr`
+  /// on demand in that case.
+  fn compile_string_defs(&self, sorts: Sorts, map: &mut HashMap<TermID, InoutStringType>) {
+    for (i, td) in self.terms.iter().enumerate() {
+      let t = TermID(i as u32);
+      if map.contains_key(&t) { continue }
+      if td.ret != (sorts.str, 0) { continue }
+      if !td.args.iter().all(|(_, ty)| matches!(ty, Type::Reg(_, 0))) { continue }
+      if let Ok(segs) = self.process_def(map, t, &self.data[td.atom].name) {
+        let ty = match &*segs {
+          [StringSeg::Str(s)] => InoutStringType::Str(s.clone()),
+          _ => InoutStringType::Gen(td.args.len(), segs),
+        };
+        map.insert(t, ty);
+      }
+    }
   }
 }
 
 impl Elaborator {
-  fn get_string_handler(&mut self, sp: Span) -> EResult<(Sorts, &mut HashMap<TermID, InoutStringType>)> {
+  fn get_string_handler(&mut self, sp: Span) -> EResult<(Sorts, StringTerms, &mut HashMap<TermID, InoutStringType>)> {
     if self.inout.string.is_none() {
-      let (s, map) = self.env.new_string_handler().map_err(|e| ElabError::new_e(sp, e))?;
-      self.inout.string = Some((s, map));
+      let (s, terms, map) = self.env.new_string_handler()
+        .map_err(|(e, span)| ElabError::new_e(span.map_or(sp, |fsp| fsp.span), e))?;
+      self.inout.string = Some((s, terms, map));
     }
-    if let Some((s, map)) = &mut self.inout.string {Ok((*s, map))}
+    if let Some((s, terms, map)) = &mut self.inout.string {Ok((*s, *terms, map))}
     else {unsafe {std::hint::unreachable_unchecked()}}
   }
 
   fn elab_output_string(&mut self, sp: Span, hs: &[SExpr]) -> EResult<()> {
-    let (sorts, _) = self.get_string_handler(sp)?;
+    let (sorts, ..) = self.get_string_handler(sp)?;
     let fsp = self.fspan(sp);
     let mut es = Vec::with_capacity(hs.len());
     for f in hs {
@@ -358,7 +717,7 @@ impl Elaborator {
     let (mut ids, heap) = build(&de);
     let exprs = is.into_iter().map(|i| ids[i].take()).collect();
     self.stmts.push(StmtTrace::OutputString(
-      Box::new(OutputString {span: fsp, heap, exprs})));
+      Box::new(OutputString {span: fsp, kind: "string", heap, exprs})));
     Ok(())
   }
 
@@ -366,7 +725,7 @@ impl Elaborator {
   /// are elaborated as type `string`, and the result is evaluated to produce a byte
   /// vector that is passed back to lisp code.
   pub fn eval_string(&mut self, fsp: FileSpan, hs: &[LispVal]) -> EResult<Vec<u8>> {
-    let (sorts, _) = self.get_string_handler(fsp.span)?;
+    let (sorts, ..) = self.get_string_handler(fsp.span)?;
     let mut es = Vec::with_capacity(hs.len());
     for e in hs {
       let sp = try_get_span(&fsp, e);
@@ -385,10 +744,12 @@ impl Elaborator {
     let (mut ids, heap) = build(&de);
     let exprs = is.into_iter().map(|i| ids[i].take()).collect::<Vec<_>>();
     let mut w = StringWriter::default();
-    let terms = &self.inout.string.as_ref().unwrap().1;
-    self.env.write_output_string(terms, &mut w, &heap, &exprs).map_err(|e| match e {
+    let bs = BudgetState::new(Budget::default());
+    let terms = &self.inout.string.as_ref().unwrap().2;
+    self.env.write_output_string(terms, &mut w, &heap, &exprs, &bs).map_err(|e| match e {
       OutputError::IOError(e) => panic!(e),
       OutputError::String(e) => ElabError::new_e(fsp.span, e),
+      OutputError::Spanned(fsp, e) => ElabError::new_e(fsp.span, e),
     })?;
     Ok(w.w)
   }
@@ -397,40 +758,255 @@ impl Elaborator {
   /// the operation of printing a string to standard out, as this would be disruptive.
   /// It is triggered only in "compile" mode, and by manual selection in server mode.
   pub fn elab_output(&mut self, sp: Span, kind: Span, hs: &[SExpr]) -> EResult<()> {
-    match self.span(kind) {
-      "string" => self.elab_output_string(sp, hs),
-      _ => Err(ElabError::new_e(kind, "unsupported output kind")),
+    match self.inout.output.get(self.span(kind)).cloned() {
+      Some(h) => h.elaborate(self, sp, hs),
+      None => Err(ElabError::new_e(kind, "unsupported output kind")),
     }
   }
 
-  /// Elaborate an `input` command. This is not implemented, as it needs to work with the
-  /// final MM0 file, which is not available. More design work is needed.
-  pub fn elab_input(&mut self, _: Span, kind: Span, _: &[SExpr]) -> EResult<()> {
-    Err(ElabError::new_e(kind, "unsupported input kind"))
+  /// The registry of output kind handlers in effect for this elaboration,
+  /// including any registered via [`InoutHandlers::register_output`]. Pass
+  /// this through to [`FrozenEnv::run_output_with`] so a custom output kind
+  /// that elaborated successfully also renders successfully, instead of
+  /// `run_output` silently falling back to the builtin-only registry.
+  pub fn output_handlers(&self) -> &OutputHandlers { &self.inout.output }
+
+  /// Elaborate an `input string` command. Unlike `output string`, there is nothing
+  /// to elaborate yet: the actual bytes only exist once the file has been fully
+  /// compiled, so all this does is check that the target environment defines the
+  /// `s0`/`s1`/`sadd`/`ch`/`x0`..`xf` terms the decoder will need, and record the
+  /// span of the command for `run_input` to report errors against. Only one
+  /// `input string` is allowed per file, since `run_input` can only produce one
+  /// decoded value; a second one is rejected here rather than silently ignored.
+  fn elab_input_string(&mut self, sp: Span, hs: &[SExpr]) -> EResult<()> {
+    self.get_string_handler(sp)?;
+    if !hs.is_empty() {
+      return Err(ElabError::new_e(sp, "'input string' does not take arguments"))
+    }
+    if self.stmts.iter().any(|s| matches!(s, StmtTrace::InputString(_))) {
+      return Err(ElabError::new_e(sp, "duplicate 'input string' command"))
+    }
+    let fsp = self.fspan(sp);
+    self.stmts.push(StmtTrace::InputString(Box::new(InputString {span: fsp})));
+    Ok(())
+  }
+
+  /// Elaborate an `input` command.
+  pub fn elab_input(&mut self, sp: Span, kind: Span, hs: &[SExpr]) -> EResult<()> {
+    match self.span(kind) {
+      "string" => self.elab_input_string(sp, hs),
+      _ => Err(ElabError::new_e(kind, "unsupported input kind")),
+    }
   }
 }
 
 impl FrozenEnv {
-  /// Run all the `output` directives in the environment,
-  /// writing output to the provided writer.
+  /// Run all the `output` directives in the environment, writing output to
+  /// the provided writer, with the default [`Budget`] and only the builtin
+  /// output kinds. If any `output` command used a kind registered via
+  /// [`InoutHandlers::register_output`], use [`FrozenEnv::run_output_with`]
+  /// instead, passing [`Elaborator::output_handlers`] from the elaboration
+  /// that produced this environment.
   pub fn run_output(&self, w: impl io::Write) -> Result<(), (FileSpan, OutputError)> {
-    let mut handler = None;
-    let mut w = StringWriter {w, hex: None};
+    self.run_output_with_budget(w, Budget::default())
+  }
+
+  /// As [`FrozenEnv::run_output`], but with an explicit expansion [`Budget`]
+  /// bounding the bytes emitted to the final output and the recursion depth
+  /// of nested defs, so a pathological `output string` def aborts with a
+  /// descriptive [`OutputError`] instead of exhausting memory or the call
+  /// stack.
+  pub fn run_output_with_budget(&self,
+      w: impl io::Write, budget: Budget) -> Result<(), (FileSpan, OutputError)> {
+    self.run_output_with(w, &builtin_output_handlers(), budget)
+  }
+
+  /// As [`FrozenEnv::run_output_with_budget`], but rendering against `handlers`
+  /// instead of only the builtins, so an output kind registered at
+  /// elaboration time (via [`InoutHandlers::register_output`]) can actually be
+  /// rendered here rather than failing with "unsupported output kind" despite
+  /// having elaborated successfully.
+  pub fn run_output_with(&self,
+      mut w: impl io::Write, handlers: &OutputHandlers, budget: Budget,
+  ) -> Result<(), (FileSpan, OutputError)> {
+    let bs = BudgetState::new(budget);
+    let mut w = StringWriter {w: &mut w as &mut dyn io::Write, hex: None};
     let env = unsafe {self.thaw()};
     for s in self.stmts() {
       if let StmtTrace::OutputString(os) = s {
-        let OutputString {span, heap, exprs} = &**os;
-        (|| -> Result<(), OutputError> {
-          let terms = {
-            handler = Some(unsafe {self.thaw()}.new_string_handler()
-              .map_err(OutputError::String)?);
-            if let Some((_, t)) = &handler {t}
-            else {unsafe {std::hint::unreachable_unchecked()}}
-          };
-        env.write_output_string(terms, &mut w, heap, exprs)
-        })().map_err(|e| (span.clone(), e))?;
+        match handlers.get(os.kind) {
+          Some(h) => h.render(env, os, &mut w, &bs).map_err(|e| match e {
+            OutputError::Spanned(sp, msg) => (sp, OutputError::String(msg)),
+            e => (os.span.clone(), e),
+          })?,
+          None => return Err((os.span.clone(), OutputError::String(
+            format!("unsupported output kind '{}'", os.kind)))),
+        }
       }
     }
     Ok(())
   }
+
+  /// Run the `input string` directive in the environment, if any, reading the
+  /// entirety of `r` and decoding it into the canonical `string` term
+  /// representation (the dual of `run_output`). The result is handed back as a
+  /// definitional `Expr` together with the span of the `input` command, so the
+  /// caller can install it as the value of a def and state theorems about "the
+  /// input". Returns `Ok(None)` if the environment has no `input string` command.
+  pub fn run_input(&self, r: impl io::Read) -> Result<Option<(FileSpan, Expr)>, (FileSpan, OutputError)> {
+    self.run_input_with_budget(r, Budget::default())
+  }
+
+  /// As [`FrozenEnv::run_input`], but aborts with a descriptive [`OutputError`]
+  /// if more than `budget.max_bytes` are read, rather than buffering an
+  /// unbounded amount of input.
+  pub fn run_input_with_budget(&self,
+      mut r: impl io::Read, budget: Budget) -> Result<Option<(FileSpan, Expr)>, (FileSpan, OutputError)> {
+    let env = unsafe {self.thaw()};
+    for s in self.stmts() {
+      if let StmtTrace::InputString(is) = s {
+        let InputString {span} = &**is;
+        return (|| -> Result<Expr, OutputError> {
+          let (_, terms, _) = env.new_string_handler()?;
+          let mut bytes = Vec::new();
+          let n = r.take((budget.max_bytes as u64).saturating_add(1)).read_to_end(&mut bytes)?;
+          if n > budget.max_bytes {
+            return Err(format!(
+              "input exceeded the {} byte budget", budget.max_bytes).as_str().into())
+          }
+          Ok(env.decode_string(&terms, &bytes))
+        })().map(|e| Some((span.clone(), e))).map_err(|e| (span.clone(), e))
+      }
+    }
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A `StringTerms`/classification map pair for the four builtin `string`
+  /// constructors plus the 16 hex digits, with arbitrary but distinct
+  /// `TermID`s — enough to drive `decode_string`/`write_node`/`write_segs`
+  /// without needing a populated `Environment` (none of these code paths look
+  /// at `self` for calls classified this way; only the unclassified-def
+  /// fallback does, which these tests don't exercise).
+  fn builtin_terms() -> (StringTerms, HashMap<TermID, InoutStringType>) {
+    use InoutStringType::*;
+    let mut hex = [TermID(0); 16];
+    for (i, t) in hex.iter_mut().enumerate() { *t = TermID(4 + i as u32); }
+    let terms = StringTerms {s0: TermID(0), s1: TermID(1), sadd: TermID(2), ch: TermID(3), hex};
+    let mut map = HashMap::new();
+    map.insert(terms.s0, S0);
+    map.insert(terms.s1, S1);
+    map.insert(terms.sadd, SAdd);
+    map.insert(terms.ch, Ch);
+    for (i, &t) in terms.hex.iter().enumerate() { map.insert(t, Hex(i as u8)); }
+    (terms, map)
+  }
+
+  fn write_expr(map: &HashMap<TermID, InoutStringType>, e: &Expr) -> Vec<u8> {
+    let env = Environment::default();
+    let bs = BudgetState::new(Budget::default());
+    let mut w = StringWriter::default();
+    let heap: Vec<ArgSlot> = e.heap.iter().enumerate().map(|(i, n)| ArgSlot::new(Arg::Local(n, i))).collect();
+    env.write_node(map, &heap, &e.head, &mut w, &bs).expect("write_node failed");
+    assert!(w.hex.is_none(), "dangling hex nibble");
+    w.w
+  }
+
+  #[test]
+  fn decode_string_round_trips() {
+    let (terms, map) = builtin_terms();
+    let env = Environment::default();
+    for bytes in [&b""[..], b"a", b"Hello, MM0!", &[0, 1, 0xfe, 0xff]] {
+      let expr = env.decode_string(&terms, bytes);
+      assert_eq!(write_expr(&map, &expr), bytes);
+    }
+  }
+
+  #[test]
+  fn gen_replay_matches_direct_expr_walk() {
+    // sadd(s1(ch(x4, x8)), sadd(s1(ch(x5, x9)), s0)) — the same shape
+    // `decode_string` builds for b"\x48\x59"==b"HY", written two ways:
+    // once by walking the `ExprNode` directly (as `write_node` does for an
+    // unclassified def's body), and once by replaying the equivalent
+    // `StringSeg` program (as `write_segs` does for a `Gen`-flattened def).
+    let (terms, map) = builtin_terms();
+    let env = Environment::default();
+    let direct = env.decode_string(&terms, b"HY");
+
+    let seg_byte = |b: u8| StringSeg::Term(terms.ch, Box::new([
+      Box::new([StringSeg::Hex(b >> 4)]), Box::new([StringSeg::Hex(b & 0xf)]),
+    ]));
+    let segs: Box<[StringSeg]> = Box::new([
+      StringSeg::Term(terms.sadd, Box::new([
+        Box::new([StringSeg::Term(terms.s1, Box::new([Box::new([seg_byte(b'H')])]))]),
+        Box::new([StringSeg::Term(terms.sadd, Box::new([
+          Box::new([StringSeg::Term(terms.s1, Box::new([Box::new([seg_byte(b'Y')])]))]),
+          Box::new([StringSeg::Term(terms.s0, Box::new([]))]),
+        ]))]),
+      ])),
+    ]);
+
+    let bs = BudgetState::new(Budget::default());
+    let mut w = StringWriter::default();
+    env.write_segs(&map, &[], &segs, &mut w, &bs).expect("write_segs failed");
+    assert_eq!(w.w, write_expr(&map, &direct));
+  }
+
+  #[test]
+  fn budget_state_enforces_byte_and_depth_limits() {
+    let bs = BudgetState::new(Budget {max_bytes: 3, max_depth: 2});
+    bs.add_bytes(3).expect("within budget");
+    bs.add_bytes(1).expect_err("exceeds byte budget");
+
+    let bs = BudgetState::new(Budget {max_bytes: usize::MAX, max_depth: 2});
+    let _g1 = bs.enter().expect("depth 1 ok");
+    let _g2 = bs.enter().expect("depth 2 ok");
+    bs.enter().expect_err("exceeds depth budget");
+  }
+
+  #[test]
+  fn string_seg_builder_merges_hex_nibbles_into_the_surrounding_str() {
+    // A hex nibble pair completes into a byte that joins whatever `str` bytes
+    // were pushed before and after it, rather than becoming its own segment;
+    // only a single *trailing* nibble at the very end survives as `Hex`.
+    let segs = StringSegBuilder::make::<()>(|b| {
+      b.push_str(b"ab").push_hex(0x1).push_hex(0x2).push_str(b"cd");
+      Ok(())
+    }).unwrap();
+    assert_eq!(&*segs, &[StringSeg::Str(vec![b'a', b'b', 0x12, b'c', b'd'].into())]);
+
+    let trailing = StringSegBuilder::make::<()>(|b| {
+      b.push_str(b"ab").push_hex(0x1);
+      Ok(())
+    }).unwrap();
+    assert_eq!(&*trailing, &[StringSeg::Str(b"ab".to_vec().into()), StringSeg::Hex(0x1)]);
+  }
+
+  #[test]
+  fn register_output_adds_without_dropping_the_builtins() {
+    // Registering a custom kind adds it to the existing registry alongside
+    // the builtin `string` kind, rather than replacing that registry.
+    let mut handlers = InoutHandlers::default();
+    assert!(handlers.output.contains_key("string"));
+    handlers.register_output("hex", Rc::new(StringOutputHandler));
+    assert!(handlers.output.contains_key("string"));
+    assert!(handlers.output.contains_key("hex"));
+  }
+
+  #[test]
+  fn check_error_without_a_span_becomes_an_unspanned_output_error() {
+    // The `Some(fsp)` branch (the actual span-precision fix) needs a real
+    // `FileSpan`, which isn't constructible from this single-file snapshot;
+    // this covers the fallback branch, which is the other half of the
+    // conversion that `StringOutputHandler::render` and
+    // `run_input_with_budget` rely on.
+    match OutputError::from(("term 'foo' not found".to_string(), None)) {
+      OutputError::String(s) => assert_eq!(s, "term 'foo' not found"),
+      e => panic!("expected an unspanned String error, got {:?}", e),
+    }
+  }
 }
\ No newline at end of file